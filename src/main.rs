@@ -5,18 +5,360 @@ mod winvol;
 
 use std::convert::TryInto;
 use std::env;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{IsTerminal, Read, Seek, SeekFrom, Write};
 use std::fs::OpenOptions;
 use std::process::exit;
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::OpenOptionsExt;
 
 use clap::derive::Clap;
+use sha1::{Digest, Sha1};
 
 use crate::opts::{DDOptions, Opts, Subcommand};
 
 
+fn bytes_to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        hex.push_str(&format!("{:02x}", b));
+    }
+    hex
+}
+
+fn parse_hash_algorithms(hash_arg: &str) -> Vec<String> {
+    hash_arg
+        .split(',')
+        .map(|piece| piece.trim().to_lowercase())
+        .filter(|piece| !piece.is_empty())
+        .collect()
+}
+
+#[cfg(target_os = "windows")]
+fn total_bytes_from_disk(source_file: &std::fs::File) -> Option<u64> {
+    winvol::get_disk_size(source_file).ok()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn total_bytes_from_disk(_source_file: &std::fs::File) -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "windows")]
+fn mark_sparse_if_windows(dest_file: &std::fs::File) -> std::io::Result<()> {
+    winvol::mark_sparse(dest_file)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn mark_sparse_if_windows(_dest_file: &std::fs::File) -> std::io::Result<()> {
+    // non-Windows filesystems create holes implicitly when we seek past zeroed data
+    Ok(())
+}
+
+fn open_dest_file(args: &DDOptions) -> std::io::Result<std::fs::File> {
+    let mut dest_file_options = OpenOptions::new();
+    dest_file_options
+        .read(args.dest_read)
+        .write(true)
+        .truncate(args.truncate_dest)
+        .create(args.create_dest);
+    if cfg!(target_os = "windows") && args.dest_excl {
+        dest_file_options.share_mode(0);
+    }
+    let mut dest_file = dest_file_options.open(&args.destination)?;
+    if args.dest_skip > 0 {
+        dest_file.seek(SeekFrom::Start(args.dest_skip))?;
+    }
+    Ok(dest_file)
+}
+
+fn format_bytes_per_sec(bytes_per_sec: f64) -> String {
+    format!("{:.2} MiB/s", bytes_per_sec / (1024.0 * 1024.0))
+}
+
+fn format_duration(total_secs: f64) -> String {
+    if !total_secs.is_finite() || total_secs < 0.0 {
+        return "unknown".to_string();
+    }
+    let total_secs = total_secs.round() as u64;
+    let hours = total_secs / 3600;
+    let minutes = (total_secs % 3600) / 60;
+    let seconds = total_secs % 60;
+    format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+}
+
+enum DestWriter {
+    Plain(std::fs::File),
+    Split(SplitFileWriter),
+    Zstd(zstd::Encoder<'static, Box<dyn Write>>),
+    Bzip2(bzip2::write::BzEncoder<Box<dyn Write>>),
+    Xz(xz2::write::XzEncoder<Box<dyn Write>>),
+}
+impl DestWriter {
+    /// Validates a `--level` value against the 1-9 range accepted by `bzip2` and `xz`,
+    /// falling back to `default` when the user did not supply one.
+    fn level_1_to_9(level: Option<u32>, default: u32, format: &str) -> std::io::Result<u32> {
+        let level = level.unwrap_or(default);
+        if level < 1 || level > 9 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("--level {} is out of range for {} (expected 1-9)", level, format),
+            ));
+        }
+        Ok(level)
+    }
+
+    fn new_compressed(sink: Box<dyn Write>, format: &str, level: Option<u32>) -> std::io::Result<DestWriter> {
+        match format {
+            "zstd" => {
+                let level_u32 = level.unwrap_or(0);
+                let level_i32: i32 = level_u32.try_into().map_err(|_| std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("--level {} is out of range for zstd (expected at most {})", level_u32, i32::MAX),
+                ))?;
+                let encoder = zstd::Encoder::new(sink, level_i32)?;
+                Ok(DestWriter::Zstd(encoder))
+            },
+            "bzip2" => {
+                let level = DestWriter::level_1_to_9(level, 6, "bzip2")?;
+                let compression = bzip2::Compression::new(level);
+                Ok(DestWriter::Bzip2(bzip2::write::BzEncoder::new(sink, compression)))
+            },
+            "xz" => {
+                let level = DestWriter::level_1_to_9(level, 6, "xz")?;
+                let encoder = xz2::write::XzEncoder::new(sink, level);
+                Ok(DestWriter::Xz(encoder))
+            },
+            other => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown compression format '{}'", other),
+            )),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            DestWriter::Plain(mut file) => file.flush(),
+            DestWriter::Split(mut writer) => writer.flush(),
+            DestWriter::Zstd(encoder) => {
+                encoder.finish()?;
+                Ok(())
+            },
+            DestWriter::Bzip2(mut encoder) => encoder.try_finish(),
+            DestWriter::Xz(mut encoder) => encoder.try_finish(),
+        }
+    }
+
+    /// Advances the destination by `len` bytes without writing anything, leaving a hole.
+    /// Only supported for a plain, single-file destination.
+    fn seek_forward(&mut self, len: u64) -> std::io::Result<()> {
+        match self {
+            DestWriter::Plain(file) => {
+                let offset: i64 = len.try_into().unwrap();
+                file.seek(SeekFrom::Current(offset))?;
+                Ok(())
+            },
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "sparse copy requires a plain, uncompressed, unsplit destination",
+            )),
+        }
+    }
+
+    /// Extends a plain destination file to its final logical length, in case the copy
+    /// ended with a run of zero blocks that were never actually written. Never shrinks
+    /// the file: a pre-existing file that is already longer than `len` (e.g. a
+    /// `--dest-skip` copy into the middle of an existing image) is left untouched.
+    fn set_final_len(&mut self, len: u64) -> std::io::Result<()> {
+        match self {
+            DestWriter::Plain(file) => {
+                if file.metadata()?.len() < len {
+                    file.set_len(len)
+                } else {
+                    Ok(())
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+}
+impl Write for DestWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DestWriter::Plain(file) => file.write(buf),
+            DestWriter::Split(writer) => writer.write(buf),
+            DestWriter::Zstd(encoder) => encoder.write(buf),
+            DestWriter::Bzip2(encoder) => encoder.write(buf),
+            DestWriter::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DestWriter::Plain(file) => file.flush(),
+            DestWriter::Split(writer) => writer.flush(),
+            DestWriter::Zstd(encoder) => encoder.flush(),
+            DestWriter::Bzip2(encoder) => encoder.flush(),
+            DestWriter::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+struct SplitFileWriter {
+    base_path: String,
+    split_size: u64,
+    create_dest: bool,
+    dest_excl: bool,
+    segment_index: u32,
+    current_file: std::fs::File,
+    bytes_in_current_segment: u64,
+}
+impl SplitFileWriter {
+    // there is no `dest_skip` support here: with several numbered segments there is no
+    // single destination file to seek into, so callers must reject that combination
+    // up front instead of passing a skip offset down to this writer
+    fn new(base_path: &str, split_size: u64, create_dest: bool, dest_excl: bool) -> std::io::Result<SplitFileWriter> {
+        let current_file = SplitFileWriter::open_segment(base_path, 0, create_dest, dest_excl)?;
+        Ok(SplitFileWriter {
+            base_path: base_path.to_string(),
+            split_size,
+            create_dest,
+            dest_excl,
+            segment_index: 0,
+            current_file,
+            bytes_in_current_segment: 0,
+        })
+    }
+
+    fn segment_path(base_path: &str, index: u32) -> String {
+        format!("{}.{:03}", base_path, index)
+    }
+
+    fn open_segment(base_path: &str, index: u32, create_dest: bool, dest_excl: bool) -> std::io::Result<std::fs::File> {
+        let mut options = OpenOptions::new();
+        options
+            .write(true)
+            .truncate(true)
+            .create(create_dest);
+        if cfg!(target_os = "windows") && dest_excl {
+            options.share_mode(0);
+        }
+        options.open(SplitFileWriter::segment_path(base_path, index))
+    }
+}
+impl Write for SplitFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.bytes_in_current_segment >= self.split_size {
+            self.segment_index += 1;
+            self.current_file = SplitFileWriter::open_segment(&self.base_path, self.segment_index, self.create_dest, self.dest_excl)?;
+            self.bytes_in_current_segment = 0;
+        }
+
+        let space_in_segment = self.split_size - self.bytes_in_current_segment;
+        let space_in_segment_usize: usize = space_in_segment.try_into().unwrap_or(usize::MAX);
+        let count_to_write = buf.len().min(space_in_segment_usize);
+        let write_count = self.current_file.write(&buf[0..count_to_write])?;
+        let write_count_u64: u64 = write_count.try_into().unwrap();
+        self.bytes_in_current_segment += write_count_u64;
+        Ok(write_count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.current_file.flush()
+    }
+}
+
+const PROGRESS_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+struct ProgressTracker {
+    start: Instant,
+    last_sample: Instant,
+    last_sample_bytes: u64,
+    total_bytes: Option<u64>,
+    is_tty: bool,
+}
+impl ProgressTracker {
+    fn new(total_bytes: Option<u64>) -> ProgressTracker {
+        let now = Instant::now();
+        ProgressTracker {
+            start: now,
+            last_sample: now,
+            last_sample_bytes: 0,
+            total_bytes,
+            is_tty: std::io::stdout().is_terminal(),
+        }
+    }
+
+    fn update(&mut self, bytes_copied: u64) {
+        if !self.is_tty {
+            return;
+        }
+        let since_last_sample = Instant::now().duration_since(self.last_sample);
+        if since_last_sample < PROGRESS_SAMPLE_INTERVAL {
+            return;
+        }
+        self.render(bytes_copied);
+    }
+
+    /// Renders the final progress line, bypassing the sample-rate throttle, so the copy
+    /// never appears to hang or stall short of completion. Like `update`, it prints
+    /// nothing when stdout is not a TTY.
+    fn finish(&mut self, bytes_copied: u64) {
+        if !self.is_tty {
+            return;
+        }
+        self.render(bytes_copied);
+    }
+
+    fn render(&mut self, bytes_copied: u64) {
+        let now = Instant::now();
+        let since_last_sample = now.duration_since(self.last_sample);
+
+        let instantaneous_bytes_per_sec = if since_last_sample.as_secs_f64() > 0.0 {
+            (bytes_copied - self.last_sample_bytes) as f64 / since_last_sample.as_secs_f64()
+        } else {
+            0.0
+        };
+        let elapsed = now.duration_since(self.start);
+        let average_bytes_per_sec = if elapsed.as_secs_f64() > 0.0 {
+            bytes_copied as f64 / elapsed.as_secs_f64()
+        } else {
+            0.0
+        };
+
+        let line = if let Some(total) = self.total_bytes {
+            let percent = if total > 0 { (bytes_copied as f64 / total as f64) * 100.0 } else { 100.0 };
+            let remaining_bytes = total.saturating_sub(bytes_copied);
+            let eta = if average_bytes_per_sec > 0.0 {
+                format_duration(remaining_bytes as f64 / average_bytes_per_sec)
+            } else {
+                "unknown".to_string()
+            };
+            format!(
+                "\r{:.1}% ({} / {} bytes), {} inst, {} avg, ETA {}   ",
+                percent, bytes_copied, total,
+                format_bytes_per_sec(instantaneous_bytes_per_sec),
+                format_bytes_per_sec(average_bytes_per_sec),
+                eta,
+            )
+        } else {
+            format!(
+                "\r{} bytes copied, {} inst, {} avg   ",
+                bytes_copied,
+                format_bytes_per_sec(instantaneous_bytes_per_sec),
+                format_bytes_per_sec(average_bytes_per_sec),
+            )
+        };
+        print!("{}", line);
+        let _ = std::io::stdout().flush();
+
+        self.last_sample = now;
+        self.last_sample_bytes = bytes_copied;
+    }
+}
+
+
 #[cfg(target_os = "windows")]
 fn do_list_windows() -> i32 {
     let disks_res = winvol::get_windows_disks();
@@ -26,23 +368,38 @@ fn do_list_windows() -> i32 {
     }
     let disks = disks_res.unwrap();
 
+    let volumes = match winvol::enumerate_volumes() {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("failed to enumerate volumes: {}", err);
+            Vec::new()
+        },
+    };
+
     for disk in &disks {
-        // try opening in turn to obtain size
-        let size = {
+        // try opening in turn to obtain size and media type
+        let (size, media_type) = {
             let opened_res = OpenOptions::new()
                 .read(true)
                 .open(disk);
             if let Err(err) = opened_res {
                 eprintln!("failed to open disk {} to obtain size: {}", disk, err);
-                None
+                (None, None)
             } else {
-                let size_res = winvol::get_disk_size(&opened_res.unwrap());
-                if let Err(err) = size_res {
+                let opened = opened_res.unwrap();
+
+                let size_res = winvol::get_disk_size(&opened);
+                let size = if let Err(err) = size_res {
                     eprintln!("failed to obtain size of disk {}: {}", disk, err);
                     None
                 } else {
                     Some(size_res.unwrap())
-                }
+                };
+
+                let media_type = winvol::has_seek_penalty(&opened)
+                    .map(|has_penalty| if has_penalty { "HDD" } else { "SSD" });
+
+                (size, media_type)
             }
         };
 
@@ -50,6 +407,22 @@ fn do_list_windows() -> i32 {
         if let Some(sz) = size {
             println!("    {}", sz);
         }
+        println!("    {}", media_type.unwrap_or("unknown media type"));
+        match winvol::find_volume_for_partition(&volumes, disk) {
+            Some(info) => {
+                if info.mount_paths.is_empty() {
+                    println!("    (no mount point)");
+                } else {
+                    println!("    mounted at: {}", info.mount_paths.join(", "));
+                }
+                if !info.label.is_empty() {
+                    println!("    label: {}", info.label);
+                }
+            },
+            None => {
+                println!("    (unmapped)");
+            },
+        }
         println!();
     }
 
@@ -57,6 +430,11 @@ fn do_list_windows() -> i32 {
 }
 
 fn do_dd(args: &DDOptions) -> i32 {
+    if args.compress.is_some() && args.dest_read {
+        eprintln!("--dest-read cannot be combined with --compress (the encoder is write-only)");
+        return 1;
+    }
+
     let mut source_file_options = OpenOptions::new();
     source_file_options
         .read(true);
@@ -78,29 +456,97 @@ fn do_dd(args: &DDOptions) -> i32 {
         }
     }
 
-    let mut dest_file_options = OpenOptions::new();
-    dest_file_options
-        .read(args.dest_read)
-        .write(true)
-        .truncate(args.truncate_dest)
-        .create(args.create_dest);
-    if cfg!(target_os = "windows") && args.dest_excl {
-        dest_file_options.share_mode(0);
+    if let Some(0) = args.split_size {
+        eprintln!("--split-size must be greater than 0");
+        return 1;
     }
-    let dest_file_res = dest_file_options
-        .open(&args.destination);
-    if let Err(err) = dest_file_res {
-        eprintln!("failed to open destination file: {}", err);
+    if args.split_size.is_some() && args.dest_read {
+        eprintln!("--dest-read cannot be combined with --split-size");
         return 1;
     }
-    let mut dest_file = dest_file_res.unwrap();
-    if args.dest_skip > 0 {
-        let seek_res = dest_file.seek(SeekFrom::Start(args.dest_skip));
-        if let Err(err) = seek_res {
-            eprintln!("failed to seek in destination file: {}", err);
+    if args.split_size.is_some() && args.dest_skip > 0 {
+        eprintln!("--dest-skip cannot be combined with --split-size (there is no single destination file to seek into)");
+        return 1;
+    }
+    if args.sparse && (args.compress.is_some() || args.split_size.is_some()) {
+        eprintln!("--sparse cannot be combined with --compress or --split-size");
+        return 1;
+    }
+
+    let mut dest_writer = if let Some(format) = &args.compress {
+        let sink: Box<dyn Write> = if let Some(split_size) = args.split_size {
+            match SplitFileWriter::new(&args.destination, split_size, args.create_dest, args.dest_excl) {
+                Ok(writer) => Box::new(writer),
+                Err(err) => {
+                    eprintln!("failed to open destination segment: {}", err);
+                    return 1;
+                },
+            }
+        } else {
+            match open_dest_file(args) {
+                Ok(file) => Box::new(file),
+                Err(err) => {
+                    eprintln!("failed to open destination file: {}", err);
+                    return 1;
+                },
+            }
+        };
+        match DestWriter::new_compressed(sink, format, args.level) {
+            Ok(writer) => writer,
+            Err(err) => {
+                eprintln!("failed to set up {} compressor: {}", format, err);
+                return 1;
+            },
+        }
+    } else if let Some(split_size) = args.split_size {
+        match SplitFileWriter::new(&args.destination, split_size, args.create_dest, args.dest_excl) {
+            Ok(writer) => DestWriter::Split(writer),
+            Err(err) => {
+                eprintln!("failed to open destination segment: {}", err);
+                return 1;
+            },
+        }
+    } else {
+        match open_dest_file(args) {
+            Ok(file) => {
+                if args.sparse {
+                    if let Err(err) = mark_sparse_if_windows(&file) {
+                        eprintln!("failed to mark destination file sparse: {}", err);
+                        return 1;
+                    }
+                }
+                DestWriter::Plain(file)
+            },
+            Err(err) => {
+                eprintln!("failed to open destination file: {}", err);
+                return 1;
+            },
+        }
+    };
+
+    let algorithms = args.hash.as_deref()
+        .map(parse_hash_algorithms)
+        .unwrap_or_else(Vec::new);
+    for algorithm in &algorithms {
+        if algorithm != "crc32" && algorithm != "sha1" {
+            eprintln!("unknown hash algorithm '{}' (expected crc32, sha1)", algorithm);
             return 1;
         }
     }
+    let mut crc32_hasher = if algorithms.iter().any(|a| a == "crc32") {
+        Some(crc32fast::Hasher::new())
+    } else {
+        None
+    };
+    let mut sha1_hasher = if algorithms.iter().any(|a| a == "sha1") {
+        Some(Sha1::new())
+    } else {
+        None
+    };
+
+    let total_bytes = args.count.or_else(|| total_bytes_from_disk(&source_file));
+    let mut progress = ProgressTracker::new(total_bytes);
+    let mut bytes_copied: u64 = 0;
 
     println!();
 
@@ -123,27 +569,98 @@ fn do_dd(args: &DDOptions) -> i32 {
         };
         let read_count_u64: u64 = read_count.try_into().unwrap();
         remaining_bytes -= read_count_u64;
+        bytes_copied += read_count_u64;
 
-        print!("\r{} bytes remain", remaining_bytes);
-        let _ = std::io::stdout().flush();
+        progress.update(bytes_copied);
 
         if read_count == 0 {
             break;
         }
 
-        let write_count = match dest_file.write(&buf[0..read_count]) {
-            Ok(wc) => wc,
-            Err(err) => {
-                eprintln!("failed to write {} bytes to destination file: {}", read_count, err);
+        if let Some(hasher) = crc32_hasher.as_mut() {
+            hasher.update(&buf[0..read_count]);
+        }
+        if let Some(hasher) = sha1_hasher.as_mut() {
+            hasher.update(&buf[0..read_count]);
+        }
+
+        if args.sparse && buf[0..read_count].iter().all(|&b| b == 0) {
+            if let Err(err) = dest_writer.seek_forward(read_count_u64) {
+                eprintln!("failed to seek past zero block in destination file: {}", err);
                 return 1;
-            },
-        };
-        if write_count != read_count {
-            eprintln!("number of bytes read ({}) does not match number of bytes written ({})", read_count, write_count);
+            }
+            continue;
+        }
+
+        // a single call to dest_writer.write() may consume less than the whole block
+        // (e.g. when a segment boundary falls in the middle of it), so loop until
+        // everything that was read has also been written
+        let mut written_so_far = 0usize;
+        while written_so_far < read_count {
+            let write_count = match dest_writer.write(&buf[written_so_far..read_count]) {
+                Ok(wc) => wc,
+                Err(err) => {
+                    eprintln!("failed to write {} bytes to destination file: {}", read_count - written_so_far, err);
+                    return 1;
+                },
+            };
+            if write_count == 0 {
+                eprintln!("destination write returned 0 bytes");
+                return 1;
+            }
+            written_so_far += write_count;
+        }
+    }
+
+    if args.sparse {
+        if let Err(err) = dest_writer.set_final_len(args.dest_skip + bytes_copied) {
+            eprintln!("failed to extend destination file to its final length: {}", err);
             return 1;
         }
     }
 
+    progress.finish(bytes_copied);
+
+    if progress.is_tty {
+        println!();
+    }
+
+    if let Err(err) = dest_writer.finish() {
+        eprintln!("failed to finalize destination stream: {}", err);
+        return 1;
+    }
+
+    let mut digests: Vec<(&str, String)> = Vec::new();
+    if let Some(hasher) = crc32_hasher {
+        let digest = bytes_to_hex(&hasher.finalize().to_be_bytes());
+        digests.push(("CRC32", digest));
+    }
+    if let Some(hasher) = sha1_hasher {
+        let digest = bytes_to_hex(&hasher.finalize());
+        digests.push(("SHA1", digest));
+    }
+    for (name, digest) in &digests {
+        println!("{}: {}", name, digest);
+    }
+
+    if let Some(expected) = &args.verify {
+        let expected_lower = expected.to_lowercase();
+        let matching_digest = digests.iter()
+            .find(|(_, digest)| digest.len() == expected_lower.len());
+        match matching_digest {
+            Some((name, digest)) => {
+                if *digest != expected_lower {
+                    eprintln!("verification failed: {} digest {} does not match expected {}", name, digest, expected_lower);
+                    return 1;
+                }
+            },
+            None => {
+                eprintln!("verification failed: no enabled hash algorithm produces a digest of the expected length");
+                return 1;
+            },
+        }
+    }
+
     0
 }
 
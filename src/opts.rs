@@ -52,4 +52,22 @@ pub struct DDOptions {
 
     #[clap(short = "R", long = "dest-read", about = "Open the destination file with read access in addition to write access.")]
     pub dest_read: bool,
+
+    #[clap(long = "hash", value_names = &["ALGORITHMS"], about = "Comma-separated list of hash algorithms to compute while copying (crc32, sha1).")]
+    pub hash: Option<String>,
+
+    #[clap(long = "verify", value_names = &["DIGEST"], about = "Expected digest (hex) to verify against whichever enabled hash algorithm produces a digest of matching length.")]
+    pub verify: Option<String>,
+
+    #[clap(long = "compress", value_names = &["FORMAT"], about = "Write the destination as a compressed stream instead of raw bytes (zstd, bzip2, xz).")]
+    pub compress: Option<String>,
+
+    #[clap(long = "level", value_names = &["LEVEL"], about = "Compression level to pass to the chosen --compress encoder.")]
+    pub level: Option<u32>,
+
+    #[clap(long = "split-size", value_names = &["BYTES"], about = "Split the destination into numbered segments of at most this many bytes each.")]
+    pub split_size: Option<u64>,
+
+    #[clap(long = "sparse", about = "Skip writing blocks that are entirely zero, leaving holes in the destination instead; incompatible with --compress and --split-size.")]
+    pub sparse: bool,
 }
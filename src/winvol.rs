@@ -14,8 +14,13 @@ use winapi::shared::ntdef::{BOOLEAN, HANDLE, NTSTATUS, PHANDLE, PULONG, PUNICODE
 use winapi::shared::ntdef::{PWSTR, UNICODE_STRING};
 use winapi::shared::ntstatus::{STATUS_NO_MORE_ENTRIES, STATUS_SUCCESS};
 use winapi::shared::winerror::HRESULT_FROM_NT;
+use winapi::um::fileapi::{
+    FindFirstVolumeW, FindNextVolumeW, FindVolumeClose,
+    GetVolumeInformationW, GetVolumePathNamesForVolumeNameW,
+};
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
 use winapi::um::ioapiset::DeviceIoControl;
-use winapi::um::winioctl::{GET_LENGTH_INFORMATION, IOCTL_DISK_GET_LENGTH_INFO};
+use winapi::um::winioctl::{FSCTL_SET_SPARSE, GET_LENGTH_INFORMATION, IOCTL_DISK_GET_LENGTH_INFO};
 use winapi::um::winnt::ACCESS_MASK;
 
 
@@ -64,6 +69,66 @@ extern "system" {
 
 const DIRECTORY_QUERY: ACCESS_MASK = 0x0001;
 
+const IOCTL_STORAGE_QUERY_PROPERTY: DWORD = 0x002D_1400;
+const STORAGE_DEVICE_SEEK_PENALTY_PROPERTY: DWORD = 7;
+const PROPERTY_STANDARD_QUERY: DWORD = 0;
+
+STRUCT! {
+    #[allow(non_snake_case)]
+    struct STORAGE_PROPERTY_QUERY {
+        PropertyId: DWORD,
+        QueryType: DWORD,
+        AdditionalParameters: [u8; 1],
+    }
+}
+
+STRUCT! {
+    #[allow(non_snake_case)]
+    struct DEVICE_SEEK_PENALTY_DESCRIPTOR {
+        Version: DWORD,
+        Size: DWORD,
+        IncursSeekPenalty: BOOLEAN,
+    }
+}
+
+const IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS: DWORD = 0x0056_0000;
+
+STRUCT! {
+    #[allow(non_snake_case)]
+    struct DISK_EXTENT {
+        DiskNumber: DWORD,
+        StartingOffset: i64,
+        ExtentLength: i64,
+    }
+}
+
+STRUCT! {
+    #[allow(non_snake_case)]
+    struct VOLUME_DISK_EXTENTS {
+        NumberOfDiskExtents: DWORD,
+        // in the (common) unspanned case there is exactly one extent; spanned
+        // volumes with several extents are not resolved by this tool
+        Extents: [DISK_EXTENT; 1],
+    }
+}
+
+
+fn to_wide_null(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0u16)).collect()
+}
+
+fn wide_to_string(buf: &[u16]) -> String {
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    String::from_utf16_lossy(&buf[0..len])
+}
+
+fn wide_multi_to_strings(buf: &[u16]) -> Vec<String> {
+    buf.split(|&c| c == 0)
+        .filter(|piece| !piece.is_empty())
+        .map(String::from_utf16_lossy)
+        .collect()
+}
+
 
 #[derive(Debug)]
 struct UnicodeStringSizeOverflow {}
@@ -278,3 +343,212 @@ pub fn get_disk_size(file: &File) -> Result<u64, IOError> {
 
     Ok(length.try_into().unwrap())
 }
+
+
+/// Classifies the disk backing `file` as rotational (HDD, `Some(true)`) or solid-state
+/// (`Some(false)`) by its reported seek penalty. Returns `None` if the classification
+/// could not be determined (the query is not supported by every device/driver).
+pub fn has_seek_penalty(file: &File) -> Option<bool> {
+    let file_handle: RawHandle = file.as_raw_handle();
+
+    let mut query = STORAGE_PROPERTY_QUERY {
+        PropertyId: STORAGE_DEVICE_SEEK_PENALTY_PROPERTY,
+        QueryType: PROPERTY_STANDARD_QUERY,
+        AdditionalParameters: [0u8],
+    };
+    let mut descriptor: DEVICE_SEEK_PENALTY_DESCRIPTOR = unsafe { zeroed() };
+    let mut bytes_returned: DWORD = 0;
+    let result: BOOL = unsafe {
+        DeviceIoControl(
+            file_handle as HANDLE,
+            IOCTL_STORAGE_QUERY_PROPERTY,
+            &mut query as *mut STORAGE_PROPERTY_QUERY as PVOID,
+            size_of::<STORAGE_PROPERTY_QUERY>().try_into().unwrap(),
+            &mut descriptor as *mut DEVICE_SEEK_PENALTY_DESCRIPTOR as PVOID,
+            size_of::<DEVICE_SEEK_PENALTY_DESCRIPTOR>().try_into().unwrap(),
+            &mut bytes_returned,
+            null_mut(),
+        )
+    };
+    if result == 0 {
+        // treat query failure as "unknown" rather than an error
+        return None;
+    }
+
+    Some(descriptor.IncursSeekPenalty != 0)
+}
+
+
+/// Identifies the physical disk extent (disk number plus byte range) a volume or
+/// partition occupies, so that a volume can be matched up with a partition device
+/// path without caring how either one spells its own name.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DiskExtentIdentity {
+    disk_number: u32,
+    starting_offset: i64,
+    extent_length: i64,
+}
+
+fn get_disk_extent(path: &str) -> Option<DiskExtentIdentity> {
+    let file = std::fs::OpenOptions::new().read(true).open(path).ok()?;
+    let file_handle: RawHandle = file.as_raw_handle();
+
+    let mut extents: VOLUME_DISK_EXTENTS = unsafe { zeroed() };
+    let extents_size: DWORD = size_of::<VOLUME_DISK_EXTENTS>().try_into().unwrap();
+    let mut bytes_returned: DWORD = 0;
+    let result: BOOL = unsafe {
+        DeviceIoControl(
+            file_handle as HANDLE,
+            IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS,
+            null_mut(),
+            0,
+            &mut extents as *mut VOLUME_DISK_EXTENTS as PVOID,
+            extents_size,
+            &mut bytes_returned,
+            null_mut(),
+        )
+    };
+    if result == 0 || extents.NumberOfDiskExtents == 0 {
+        return None;
+    }
+
+    let first = &extents.Extents[0];
+    Some(DiskExtentIdentity {
+        disk_number: first.DiskNumber,
+        starting_offset: first.StartingOffset,
+        extent_length: first.ExtentLength,
+    })
+}
+
+pub struct VolumeInfo {
+    pub mount_paths: Vec<String>,
+    pub label: String,
+}
+
+struct FindVolumeHandle {
+    handle: HANDLE,
+}
+impl Drop for FindVolumeHandle {
+    fn drop(&mut self) {
+        unsafe {
+            FindVolumeClose(self.handle);
+        }
+    }
+}
+
+/// Enumerates every mounted volume, keyed by the physical disk extent it occupies,
+/// together with its mount point(s) and volume label. Used to annotate the raw
+/// partition device paths produced by `get_windows_disks` with something a user
+/// can actually recognize.
+pub fn enumerate_volumes() -> Result<Vec<(DiskExtentIdentity, VolumeInfo)>, IOError> {
+    let mut ret = Vec::new();
+
+    let mut volume_name_buf = [0u16; 1024];
+    let find_handle = unsafe {
+        FindFirstVolumeW(volume_name_buf.as_mut_ptr(), volume_name_buf.len().try_into().unwrap())
+    };
+    if find_handle == INVALID_HANDLE_VALUE {
+        return Err(IOError::last_os_error());
+    }
+    let _find_guard = FindVolumeHandle { handle: find_handle };
+
+    loop {
+        let volume_name = wide_to_string(&volume_name_buf);
+
+        // GetVolumePathNamesForVolumeNameW and friends want the trailing backslash;
+        // IOCTLs on the other hand want it stripped
+        let volume_path_no_backslash = volume_name.trim_end_matches('\\');
+
+        if let Some(extent) = get_disk_extent(volume_path_no_backslash) {
+            let mount_paths = get_volume_mount_paths(&volume_name).unwrap_or_default();
+            let label = get_volume_label(&volume_name).unwrap_or_default();
+            ret.push((extent, VolumeInfo { mount_paths, label }));
+        }
+
+        let next_result = unsafe {
+            FindNextVolumeW(find_handle, volume_name_buf.as_mut_ptr(), volume_name_buf.len().try_into().unwrap())
+        };
+        if next_result == 0 {
+            // ERROR_NO_MORE_FILES is the expected way for this loop to end
+            break;
+        }
+    }
+
+    Ok(ret)
+}
+
+fn get_volume_mount_paths(volume_name: &str) -> Result<Vec<String>, IOError> {
+    let volume_name_wide = to_wide_null(volume_name);
+    let mut buf = [0u16; 4096];
+    let mut return_length: DWORD = 0;
+    let result: BOOL = unsafe {
+        GetVolumePathNamesForVolumeNameW(
+            volume_name_wide.as_ptr(),
+            buf.as_mut_ptr(),
+            buf.len().try_into().unwrap(),
+            &mut return_length,
+        )
+    };
+    if result == 0 {
+        return Err(IOError::last_os_error());
+    }
+
+    Ok(wide_multi_to_strings(&buf))
+}
+
+fn get_volume_label(volume_name: &str) -> Result<String, IOError> {
+    let volume_name_wide = to_wide_null(volume_name);
+    let mut label_buf = [0u16; 256];
+    let result: BOOL = unsafe {
+        GetVolumeInformationW(
+            volume_name_wide.as_ptr(),
+            label_buf.as_mut_ptr(),
+            label_buf.len().try_into().unwrap(),
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            0,
+        )
+    };
+    if result == 0 {
+        return Err(IOError::last_os_error());
+    }
+
+    Ok(wide_to_string(&label_buf))
+}
+
+/// Looks up the volume mounted at the physical disk extent of the partition at `path`,
+/// for annotating its listing; `None` means no mounted volume could be matched to it.
+pub fn find_volume_for_partition<'a>(volumes: &'a [(DiskExtentIdentity, VolumeInfo)], path: &str) -> Option<&'a VolumeInfo> {
+    let extent = get_disk_extent(path)?;
+    volumes.iter()
+        .find(|(candidate, _)| *candidate == extent)
+        .map(|(_, info)| info)
+}
+
+
+/// Marks `file` as a sparse file, allowing subsequently-seeked-over ranges to become
+/// unallocated holes instead of being physically zero-filled on disk.
+pub fn mark_sparse(file: &File) -> Result<(), IOError> {
+    let file_handle: RawHandle = file.as_raw_handle();
+
+    let mut bytes_returned: DWORD = 0;
+    let result: BOOL = unsafe {
+        DeviceIoControl(
+            file_handle as HANDLE,
+            FSCTL_SET_SPARSE,
+            null_mut(),
+            0,
+            null_mut(),
+            0,
+            &mut bytes_returned,
+            null_mut(),
+        )
+    };
+    if result == 0 {
+        return Err(IOError::last_os_error());
+    }
+
+    Ok(())
+}